@@ -38,10 +38,36 @@ const MIN_ALIGN: usize = 16;
 
 extern crate alloc;
 
-use alloc::heap::{Alloc, AllocErr, Layout, Excess, CannotReallocInPlace};
+use core::cmp;
+use core::ptr;
+
+use alloc::heap::{Alloc, AllocErr, GlobalAlloc, Layout, Excess, CannotReallocInPlace};
 
 pub struct System;
 
+unsafe impl GlobalAlloc for System {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        Alloc::alloc(&mut &*self, layout).unwrap_or(0 as *mut u8)
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        Alloc::alloc_zeroed(&mut &*self, layout).unwrap_or(0 as *mut u8)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        Alloc::dealloc(&mut &*self, ptr, layout)
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        Alloc::realloc(&mut &*self, ptr, layout, new_layout).unwrap_or(0 as *mut u8)
+    }
+}
+
 unsafe impl Alloc for System {
     #[inline]
     unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
@@ -107,20 +133,140 @@ unsafe impl Alloc for System {
     }
 }
 
+impl System {
+    // Shared by `realloc`'s alignment-change case, its
+    // `align() > MIN_ALIGN` case and its `align() > size()` case: alloc the
+    // new layout, copy the overlapping bytes over, then free the old block.
+    pub(crate) unsafe fn realloc_fallback(&self,
+                                          ptr: *mut u8,
+                                          old_layout: Layout,
+                                          new_layout: Layout) -> Result<*mut u8, AllocErr> {
+        let result = Alloc::alloc(&mut &*self, new_layout.clone());
+        if let Ok(new_ptr) = result {
+            let size = cmp::min(old_layout.size(), new_layout.size());
+            ptr::copy_nonoverlapping(ptr, new_ptr, size);
+            Alloc::dealloc(&mut &*self, ptr, old_layout);
+        }
+        result
+    }
+}
+
+/// Allocates from the 3DS's contiguous "linear" heap (libctru's
+/// `linearAlloc`/`linearFree`) instead of the regular `malloc` heap that
+/// [`System`] uses. GPU and DMA buffers (vertex arrays, textures, display
+/// transfer output, ...) must live in linear memory, so code that builds
+/// such buffers with `Box`/`Vec` should use this as their allocator instead
+/// of the default one.
+#[cfg(target_os = "horizon")]
+pub struct LinearAllocator;
+
+#[cfg(target_os = "horizon")]
+unsafe impl GlobalAlloc for LinearAllocator {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        Alloc::alloc(&mut &*self, layout).unwrap_or(0 as *mut u8)
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        Alloc::alloc_zeroed(&mut &*self, layout).unwrap_or(0 as *mut u8)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        Alloc::dealloc(&mut &*self, ptr, layout)
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        Alloc::realloc(&mut &*self, ptr, layout, new_layout).unwrap_or(0 as *mut u8)
+    }
+}
+
+#[cfg(target_os = "horizon")]
+unsafe impl Alloc for LinearAllocator {
+    #[inline]
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        (&*self).alloc(layout)
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&mut self, layout: Layout)
+        -> Result<*mut u8, AllocErr>
+    {
+        (&*self).alloc_zeroed(layout)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        (&*self).dealloc(ptr, layout)
+    }
+
+    #[inline]
+    unsafe fn realloc(&mut self,
+                      ptr: *mut u8,
+                      old_layout: Layout,
+                      new_layout: Layout) -> Result<*mut u8, AllocErr> {
+        (&*self).realloc(ptr, old_layout, new_layout)
+    }
+
+    fn oom(&mut self, err: AllocErr) -> ! {
+        (&*self).oom(err)
+    }
+
+    #[inline]
+    fn usable_size(&self, layout: &Layout) -> (usize, usize) {
+        (&self).usable_size(layout)
+    }
+
+    #[inline]
+    unsafe fn alloc_excess(&mut self, layout: Layout) -> Result<Excess, AllocErr> {
+        (&*self).alloc_excess(layout)
+    }
+
+    #[inline]
+    unsafe fn realloc_excess(&mut self,
+                             ptr: *mut u8,
+                             layout: Layout,
+                             new_layout: Layout) -> Result<Excess, AllocErr> {
+        (&*self).realloc_excess(ptr, layout, new_layout)
+    }
+
+    #[inline]
+    unsafe fn grow_in_place(&mut self,
+                            ptr: *mut u8,
+                            layout: Layout,
+                            new_layout: Layout) -> Result<(), CannotReallocInPlace> {
+        (&*self).grow_in_place(ptr, layout, new_layout)
+    }
+
+    #[inline]
+    unsafe fn shrink_in_place(&mut self,
+                              ptr: *mut u8,
+                              layout: Layout,
+                              new_layout: Layout) -> Result<(), CannotReallocInPlace> {
+        (&*self).shrink_in_place(ptr, layout, new_layout)
+    }
+}
+
 mod platform {
     extern crate libc;
 
     use core::cmp;
+    use core::mem;
     use core::ptr;
 
     use MIN_ALIGN;
     use ::System;
     use ::alloc::heap::{Alloc, AllocErr, Layout};
+    #[cfg(any(target_os = "linux", target_os = "android", target_env = "newlib"))]
+    use ::alloc::heap::{CannotReallocInPlace, Excess};
 
     unsafe impl<'a> Alloc for &'a System {
         #[inline]
         unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
-            let ptr = if layout.align() <= MIN_ALIGN {
+            let ptr = if layout.align() <= MIN_ALIGN && layout.align() <= layout.size() {
                 libc::malloc(layout.size()) as *mut u8
             } else {
                 aligned_malloc(&layout)
@@ -136,7 +282,7 @@ mod platform {
         unsafe fn alloc_zeroed(&mut self, layout: Layout)
             -> Result<*mut u8, AllocErr>
         {
-            if layout.align() <= MIN_ALIGN {
+            if layout.align() <= MIN_ALIGN && layout.align() <= layout.size() {
                 let ptr = libc::calloc(layout.size(), 1) as *mut u8;
                 if !ptr.is_null() {
                     Ok(ptr)
@@ -162,13 +308,9 @@ mod platform {
                           ptr: *mut u8,
                           old_layout: Layout,
                           new_layout: Layout) -> Result<*mut u8, AllocErr> {
-            if old_layout.align() != new_layout.align() {
-                return Err(AllocErr::Unsupported {
-                    details: "cannot change alignment on `realloc`",
-                })
-            }
-
-            if new_layout.align() <= MIN_ALIGN {
+            if old_layout.align() == new_layout.align()
+                && new_layout.align() <= MIN_ALIGN
+                && new_layout.align() <= new_layout.size() {
                 let ptr = libc::realloc(ptr as *mut libc::c_void, new_layout.size());
                 if !ptr.is_null() {
                     Ok(ptr as *mut u8)
@@ -176,13 +318,63 @@ mod platform {
                     Err(AllocErr::Exhausted { request: new_layout })
                 }
             } else {
-                let res = self.alloc(new_layout.clone());
-                if let Ok(new_ptr) = res {
-                    let size = cmp::min(old_layout.size(), new_layout.size());
-                    ptr::copy_nonoverlapping(ptr, new_ptr, size);
-                    self.dealloc(ptr, old_layout);
-                }
-                res
+                self.realloc_fallback(ptr, old_layout, new_layout)
+            }
+        }
+
+        // `malloc_usable_size` reports how much of the block the allocator
+        // actually carved out, which is normally >= the requested size. On
+        // platforms/libcs that provide it we can use that slack to satisfy
+        // `alloc_excess`/`realloc_excess` and to grow or shrink in place
+        // without touching the allocation; elsewhere we fall back to the
+        // trait's conservative defaults.
+        #[cfg(any(target_os = "linux", target_os = "android", target_env = "newlib"))]
+        #[inline]
+        unsafe fn alloc_excess(&mut self, layout: Layout) -> Result<Excess, AllocErr> {
+            let size = layout.size();
+            self.alloc(layout).map(|ptr| {
+                let excess = cmp::max(size, libc::malloc_usable_size(ptr as *mut libc::c_void));
+                Excess(ptr, excess)
+            })
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android", target_env = "newlib"))]
+        #[inline]
+        unsafe fn realloc_excess(&mut self,
+                                 ptr: *mut u8,
+                                 layout: Layout,
+                                 new_layout: Layout) -> Result<Excess, AllocErr> {
+            let size = new_layout.size();
+            self.realloc(ptr, layout, new_layout).map(|ptr| {
+                let excess = cmp::max(size, libc::malloc_usable_size(ptr as *mut libc::c_void));
+                Excess(ptr, excess)
+            })
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android", target_env = "newlib"))]
+        #[inline]
+        unsafe fn grow_in_place(&mut self,
+                                ptr: *mut u8,
+                                layout: Layout,
+                                new_layout: Layout) -> Result<(), CannotReallocInPlace> {
+            self.shrink_in_place(ptr, layout, new_layout)
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android", target_env = "newlib"))]
+        #[inline]
+        unsafe fn shrink_in_place(&mut self,
+                                  ptr: *mut u8,
+                                  layout: Layout,
+                                  new_layout: Layout) -> Result<(), CannotReallocInPlace> {
+            if layout.align() != new_layout.align() {
+                return Err(CannotReallocInPlace);
+            }
+
+            let usable = libc::malloc_usable_size(ptr as *mut libc::c_void);
+            if new_layout.size() <= usable {
+                Ok(())
+            } else {
+                Err(CannotReallocInPlace)
             }
         }
 
@@ -214,9 +406,29 @@ mod platform {
         }
     }
 
+    // `posix_memalign`/`memalign` require the alignment to be a power of
+    // two that is also a multiple of `size_of::<*const c_void>()`, and have
+    // no defined behavior for alignments they can't satisfy. Round small
+    // requests up to that minimum and reject absurdly large ones so the
+    // caller gets a clean null/`Exhausted` instead of UB.
+    #[inline]
+    fn checked_align(align: usize) -> Option<usize> {
+        let align = cmp::max(align, mem::size_of::<*const libc::c_void>());
+        if align > (isize::max_value() as usize) {
+            None
+        } else {
+            Some(align)
+        }
+    }
+
     #[cfg(any(target_os = "android", target_os = "redox", target_env = "newlib"))]
     #[inline]
     unsafe fn aligned_malloc(layout: &Layout) -> *mut u8 {
+        let align = match checked_align(layout.align()) {
+            Some(align) => align,
+            None => return ptr::null_mut(),
+        };
+
         // On android we currently target API level 9 which unfortunately
         // doesn't have the `posix_memalign` API used below. Instead we use
         // `memalign`, but this unfortunately has the property on some systems
@@ -234,14 +446,19 @@ mod platform {
         // [3]: https://bugs.chromium.org/p/chromium/issues/detail?id=138579
         // [4]: https://chromium.googlesource.com/chromium/src/base/+/master/
 		//                                       /memory/aligned_memory.cc
-        libc::memalign(layout.align(), layout.size()) as *mut u8
+        libc::memalign(align, layout.size()) as *mut u8
     }
 
     #[cfg(not(any(target_os = "android", target_os = "redox", target_env = "newlib")))]
     #[inline]
     unsafe fn aligned_malloc(layout: &Layout) -> *mut u8 {
+        let align = match checked_align(layout.align()) {
+            Some(align) => align,
+            None => return ptr::null_mut(),
+        };
+
         let mut out = ptr::null_mut();
-        let ret = libc::posix_memalign(&mut out, layout.align(), layout.size());
+        let ret = libc::posix_memalign(&mut out, align, layout.size());
         if ret != 0 {
             ptr::null_mut()
         } else {
@@ -249,3 +466,84 @@ mod platform {
         }
     }
 }
+
+#[cfg(target_os = "horizon")]
+mod linear {
+    extern crate ctru_sys as ctru;
+    extern crate libc;
+
+    use core::cmp;
+    use core::ptr;
+
+    use ::LinearAllocator;
+    use ::alloc::heap::{Alloc, AllocErr, Layout};
+
+    unsafe impl<'a> Alloc for &'a LinearAllocator {
+        #[inline]
+        unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+            let ptr = ctru::linearAlloc_aligned(layout.size(), layout.align()) as *mut u8;
+            if !ptr.is_null() {
+                Ok(ptr)
+            } else {
+                Err(AllocErr::Exhausted { request: layout })
+            }
+        }
+
+        #[inline]
+        unsafe fn alloc_zeroed(&mut self, layout: Layout)
+            -> Result<*mut u8, AllocErr>
+        {
+            let ret = self.alloc(layout.clone());
+            if let Ok(ptr) = ret {
+                ptr::write_bytes(ptr, 0, layout.size());
+            }
+            ret
+        }
+
+        #[inline]
+        unsafe fn dealloc(&mut self, ptr: *mut u8, _layout: Layout) {
+            ctru::linearFree(ptr as *mut libc::c_void)
+        }
+
+        #[inline]
+        unsafe fn realloc(&mut self,
+                          ptr: *mut u8,
+                          old_layout: Layout,
+                          new_layout: Layout) -> Result<*mut u8, AllocErr> {
+            // The linear heap has no in-place realloc (unlike `libc::realloc`
+            // for the `System` heap), so always alloc a fresh block, copy the
+            // overlapping bytes over, and free the old one.
+            let res = self.alloc(new_layout.clone());
+            if let Ok(new_ptr) = res {
+                let size = cmp::min(old_layout.size(), new_layout.size());
+                ptr::copy_nonoverlapping(ptr, new_ptr, size);
+                self.dealloc(ptr, old_layout);
+            }
+            res
+        }
+
+        fn oom(&mut self, err: AllocErr) -> ! {
+            use core::fmt::{self, Write};
+
+            // See `System`'s `oom`: print to stderr and abort without
+            // allocating, since we're already in an OOM situation.
+            drop(writeln!(Stderr, "fatal runtime error: {}", err));
+            unsafe {
+                ::core::intrinsics::abort();
+            }
+
+            struct Stderr;
+
+            impl Write for Stderr {
+                fn write_str(&mut self, s: &str) -> fmt::Result {
+                    unsafe {
+                        libc::write(libc::STDERR_FILENO,
+                                    s.as_ptr() as *const libc::c_void,
+                                    s.len());
+                    }
+                    Ok(())
+                }
+            }
+        }
+    }
+}